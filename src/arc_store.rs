@@ -0,0 +1,176 @@
+use crate::dht_arc::DhtArc;
+use crate::{AgentInfo, P2pStore, SplitArc};
+use std::sync::Mutex;
+
+/// Error type shared by all [`ArcStore`] implementations. Its only variant wraps a
+/// `rusqlite::Error` from the sqlite-backed [`P2pStore`]; [`MemoryArcStore`] never returns one.
+#[derive(Debug)]
+pub enum ArcStoreError {
+    Sqlite(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for ArcStoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Sqlite(e)
+    }
+}
+
+impl std::fmt::Display for ArcStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sqlite(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ArcStoreError {}
+
+pub type ArcStoreResult<T> = Result<T, ArcStoreError>;
+
+/// A backend capable of indexing agents by their storage arc. Extracted so the arc index
+/// isn't hard-wired to rusqlite: callers that only need the covering/overlap queries can
+/// plug in [`MemoryArcStore`] instead of linking SQLite at all.
+pub trait ArcStore {
+    fn insert(&self, agent_info: &AgentInfo) -> ArcStoreResult<()>;
+    fn dump(&self) -> ArcStoreResult<()>;
+    fn count_agents_covering_loc(&self, loc: u32) -> ArcStoreResult<usize>;
+    fn count_agents_overlaping_arc(&self, arc: DhtArc) -> ArcStoreResult<usize>;
+}
+
+impl ArcStore for P2pStore {
+    fn insert(&self, agent_info: &AgentInfo) -> ArcStoreResult<()> {
+        Ok(P2pStore::insert(self, agent_info)?)
+    }
+
+    fn dump(&self) -> ArcStoreResult<()> {
+        Ok(P2pStore::dump(self)?)
+    }
+
+    fn count_agents_covering_loc(&self, loc: u32) -> ArcStoreResult<usize> {
+        Ok(P2pStore::count_agents_covering_loc(self, loc)?)
+    }
+
+    fn count_agents_overlaping_arc(&self, arc: DhtArc) -> ArcStoreResult<usize> {
+        Ok(P2pStore::count_agents_overlaping_arc(self, arc)?)
+    }
+}
+
+/// A pure-Rust, in-memory [`ArcStore`] for tests and lightweight embedders that shouldn't
+/// have to link SQLite. Answers the covering/overlap queries directly against the agents'
+/// [`SplitArc`] intervals, mirroring the same predicates as the SQL `WHERE` clauses.
+#[derive(Default)]
+pub struct MemoryArcStore {
+    agents: Mutex<Vec<AgentInfo>>,
+}
+
+impl MemoryArcStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ArcStore for MemoryArcStore {
+    fn insert(&self, agent_info: &AgentInfo) -> ArcStoreResult<()> {
+        let mut agents = self.agents.lock().unwrap();
+        agents.retain(|a| a.key != agent_info.key);
+        agents.push(agent_info.clone());
+        Ok(())
+    }
+
+    fn dump(&self) -> ArcStoreResult<()> {
+        for agent in self.agents.lock().unwrap().iter() {
+            let split: SplitArc = agent.storage_arc.clone().into();
+            println!(
+                "{:?}-{:?} + {:?}-{:?}",
+                split.start_1, split.end_1, split.start_2, split.end_2
+            );
+        }
+        Ok(())
+    }
+
+    fn count_agents_covering_loc(&self, loc: u32) -> ArcStoreResult<usize> {
+        Ok(self
+            .agents
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|a| arc_covers_loc(&a.storage_arc.clone().into(), loc))
+            .count())
+    }
+
+    fn count_agents_overlaping_arc(&self, arc: DhtArc) -> ArcStoreResult<usize> {
+        let query: SplitArc = arc.into();
+        Ok(self
+            .agents
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|a| arcs_overlap(&query, &a.storage_arc.clone().into()))
+            .count())
+    }
+}
+
+fn arc_covers_loc(stored: &SplitArc, loc: u32) -> bool {
+    let first = matches!((stored.start_1, stored.end_1), (Some(s), Some(e)) if loc >= s && loc <= e);
+    let second = matches!((stored.start_2, stored.end_2), (Some(s), Some(e)) if loc >= s && loc <= e);
+    first || second
+}
+
+// mirrors the `?1 <= arc_end1 AND ?2 >= arc_start1` / `?3.. AND ?4..` predicate used by
+// `count_agents_overlaping_arc`'s SQL: query interval 1 is only checked against the stored
+// interval 1, and likewise for interval 2 — it does not cross-check interval 1 vs 2.
+fn arcs_overlap(query: &SplitArc, stored: &SplitArc) -> bool {
+    let first = matches!(
+        (query.start_1, query.end_1, stored.start_1, stored.end_1),
+        (Some(qs), Some(qe), Some(ss), Some(se)) if qs <= se && qe >= ss
+    );
+    let second = matches!(
+        (query.start_2, query.end_2, stored.start_2, stored.end_2),
+        (Some(qs), Some(qe), Some(ss), Some(se)) if qs <= se && qe >= ss
+    );
+    first || second
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent_with_arc(arc: DhtArc) -> AgentInfo {
+        let mut info = AgentInfo::new_rand();
+        info.storage_arc = arc;
+        info
+    }
+
+    /// `MemoryArcStore` is supposed to answer the same covering/overlap predicates as the
+    /// sqlite-backed `P2pStore`, so the two must agree on every input, including the SQL
+    /// predicate's "no cross-check between interval 1 and 2" quirk.
+    #[test]
+    fn memory_and_sqlite_backends_agree_on_coverage_and_overlap() {
+        let sqlite_store = P2pStore::new_in_memory().unwrap();
+        let mem_store = MemoryArcStore::new();
+
+        let agents = [
+            agent_with_arc(DhtArc::new(0, 100)),
+            agent_with_arc(DhtArc::new(u32::MAX, 50)),
+            agent_with_arc(DhtArc::new(u32::MAX / 2, u32::MAX / 4)),
+        ];
+        for agent in &agents {
+            ArcStore::insert(&sqlite_store, agent).unwrap();
+            mem_store.insert(agent).unwrap();
+        }
+
+        for loc in [0, 50, u32::MAX / 2, u32::MAX] {
+            assert_eq!(
+                ArcStore::count_agents_covering_loc(&sqlite_store, loc).unwrap(),
+                mem_store.count_agents_covering_loc(loc).unwrap(),
+                "mismatch at loc {loc}"
+            );
+        }
+
+        let probe = DhtArc::new(u32::MAX / 2, u32::MAX / 8);
+        assert_eq!(
+            ArcStore::count_agents_overlaping_arc(&sqlite_store, probe.clone()).unwrap(),
+            mem_store.count_agents_overlaping_arc(probe).unwrap()
+        );
+    }
+}
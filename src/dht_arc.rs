@@ -0,0 +1,40 @@
+//! Minimal stand-in for holochain's `DhtArc` type, just enough of its shape and `range()`
+//! behavior for this spike to exercise `SplitArc` against.
+use std::ops::Bound;
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct Loc(u32);
+
+impl From<Loc> for u32 {
+    fn from(l: Loc) -> u32 {
+        l.0
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DhtArc {
+    pub center_loc: Loc,
+    pub half_length: u32,
+}
+
+impl DhtArc {
+    pub fn new(center_loc: u32, half_length: u32) -> Self {
+        Self {
+            center_loc: Loc(center_loc),
+            half_length,
+        }
+    }
+
+    pub fn range(&self) -> (Bound<u32>, Bound<u32>) {
+        if self.half_length == 0 {
+            return (
+                Bound::Excluded(self.center_loc.0),
+                Bound::Excluded(self.center_loc.0),
+            );
+        }
+        let center = self.center_loc.0;
+        let start = center.wrapping_sub(self.half_length);
+        let end = center.wrapping_add(self.half_length);
+        (Bound::Included(start), Bound::Included(end))
+    }
+}
@@ -1,8 +1,111 @@
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::functions;
 use rusqlite::*;
 
 pub mod dht_arc;
 use dht_arc::*;
 
+mod arc_store;
+mod migration;
+use arc_store::{ArcStore, MemoryArcStore};
+
+/// Pooled access to a `p2p_store` sqlite database, safe to share between many concurrent
+/// `insert`/`count_agents_*` callers.
+pub struct P2pStore {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl P2pStore {
+    /// Open (or create) the store behind an in-memory connection pool.
+    pub fn new_in_memory() -> Result<Self> {
+        Self::open(SqliteConnectionManager::memory())
+    }
+
+    /// Open (or create) the store behind a connection pool backed by a file on disk.
+    pub fn open_file(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        Self::open(SqliteConnectionManager::file(path))
+    }
+
+    fn open(manager: SqliteConnectionManager) -> Result<Self> {
+        Self::open_pool(manager.with_init(|c| install_functions(c)))
+    }
+
+    fn open_pool(manager: SqliteConnectionManager) -> Result<Self> {
+        let pool = Pool::new(manager).map_err(|e| Error::ToSqlConversionFailure(e.into()))?;
+        let mut con = pool.get().map_err(|e| Error::ToSqlConversionFailure(e.into()))?;
+        migration::migrate(&mut con)?;
+        Ok(Self { pool })
+    }
+
+    fn get(&self) -> Result<r2d2::PooledConnection<SqliteConnectionManager>> {
+        self.pool
+            .get()
+            .map_err(|e| Error::ToSqlConversionFailure(e.into()))
+    }
+
+    pub fn insert(&self, agent_info: &AgentInfo) -> Result<()> {
+        let con = self.get()?;
+        insert(&con, agent_info)
+    }
+
+    pub fn dump(&self) -> Result<()> {
+        let con = self.get()?;
+        dump(&con)
+    }
+
+    pub fn count_agents_covering_loc(&self, loc: u32) -> Result<usize> {
+        let con = self.get()?;
+        count_agents_covering_loc(&con, loc)
+    }
+
+    pub fn count_agents_overlaping_arc(&self, arc: DhtArc) -> Result<usize> {
+        let con = self.get()?;
+        count_agents_overlaping_arc(&con, arc)
+    }
+
+    pub fn analyze_coverage(&self, histogram_buckets: Option<u32>) -> Result<CoverageReport> {
+        let con = self.get()?;
+        analyze_coverage(&con, histogram_buckets)
+    }
+
+    pub fn prune_before(&self, cutoff_ms: u64) -> Result<usize> {
+        let con = self.get()?;
+        prune_before(&con, cutoff_ms)
+    }
+
+    pub fn prune_expired(&self, now_ms: u64, ttl_ms: u64) -> Result<usize> {
+        let con = self.get()?;
+        prune_expired(&con, now_ms, ttl_ms)
+    }
+
+    pub fn query_agents_for_loc(&self, loc: u32, limit: u32) -> Result<Vec<AgentInfo>> {
+        let con = self.get()?;
+        query_agents_for_loc(&con, loc, limit)
+    }
+}
+
+#[cfg(feature = "sqlcipher")]
+impl P2pStore {
+    /// Open (or create) an at-rest encrypted store, keyed with `passphrase` via SQLCipher's
+    /// `PRAGMA key`. The key is applied to every pooled connection before any table creation,
+    /// so the serialized `blob` column (a full signed `AgentInfo`, including the 32-byte
+    /// agent key) is never written to disk in the clear.
+    pub fn open_encrypted(path: impl AsRef<std::path::Path>, passphrase: &str) -> Result<Self> {
+        let passphrase = passphrase.to_string();
+        let manager = SqliteConnectionManager::file(path).with_init(move |c| {
+            c.pragma_update(None, "key", &passphrase)?;
+            install_functions(c)
+        });
+        Self::open_pool(manager)
+    }
+
+    /// Rotate the store's SQLCipher key to `new_passphrase`.
+    pub fn rekey(&self, new_passphrase: &str) -> Result<()> {
+        self.get()?.pragma_update(None, "rekey", new_passphrase)
+    }
+}
+
 #[derive(Debug)]
 pub struct SplitArc {
     pub start_1: Option<u32>,
@@ -147,6 +250,175 @@ fn count_agents_covering_loc(con: &Connection, loc: u32) -> Result<usize> {
     stmt.query_row(params![loc], |r| r.get(0))
 }
 
+/// An inclusive `[start, end]` range of the u32 ring with zero agents covering it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageGap {
+    pub start: u32,
+    pub end: u32,
+}
+
+/// Result of a single sweep over `p2p_store`'s arc intervals.
+#[derive(Debug, Clone)]
+pub struct CoverageReport {
+    /// The smallest number of agents covering any location on the ring.
+    pub min_coverage: usize,
+    /// Maximal spans covered by zero agents.
+    pub gaps: Vec<CoverageGap>,
+    /// Average agent coverage, weighted by location, for each of the requested equal-width
+    /// buckets (the last bucket absorbs the remainder when `2^32` doesn't divide evenly).
+    pub histogram: Option<Vec<usize>>,
+}
+
+/// Sweep every stored arc's intervals once to compute the DHT redundancy profile over the
+/// whole u32 ring: minimum coverage, uncovered gaps, and an optional coverage histogram.
+fn analyze_coverage(con: &Connection, histogram_buckets: Option<u32>) -> Result<CoverageReport> {
+    let mut stmt = con.prepare("SELECT arc_start1, arc_end1, arc_start2, arc_end2 FROM p2p_store")?;
+    let rows = stmt.query_map([], |r| {
+        let s1: Option<u32> = r.get(0)?;
+        let e1: Option<u32> = r.get(1)?;
+        let s2: Option<u32> = r.get(2)?;
+        let e2: Option<u32> = r.get(3)?;
+        Ok((s1, e1, s2, e2))
+    })?;
+
+    // (position, delta) events. Positions are kept in i64 so the `end + 1` endpoint of an
+    // interval touching u32::MAX never has to wrap; we just drop that endpoint instead,
+    // since no decrement is needed if the interval already runs to the edge of the ring.
+    let mut events: Vec<(i64, i64)> = Vec::new();
+    let mut push_interval = |start: u32, end: u32| {
+        events.push((start as i64, 1));
+        if end != u32::MAX {
+            events.push((end as i64 + 1, -1));
+        }
+    };
+    for row in rows {
+        let (s1, e1, s2, e2) = row?;
+        if let (Some(s1), Some(e1)) = (s1, e1) {
+            push_interval(s1, e1);
+        }
+        if let (Some(s2), Some(e2)) = (s2, e2) {
+            push_interval(s2, e2);
+        }
+    }
+    events.sort_unstable_by_key(|(pos, _)| *pos);
+
+    let bucket_count = histogram_buckets.unwrap_or(0) as usize;
+    // total (coverage * overlap_length) per bucket; divided into an average once the sweep
+    // is done, since a single span can partially overlap several buckets.
+    let mut histogram_sums = vec![0u128; bucket_count];
+    let total_locations = u32::MAX as u64 + 1;
+    let bucket_width = if bucket_count > 0 {
+        total_locations / bucket_count as u64
+    } else {
+        0
+    };
+
+    let mut coverage: i64 = 0;
+    let mut prev: i64 = 0;
+    let mut min_coverage = usize::MAX;
+    let mut gaps = Vec::new();
+
+    // walk the sorted events, grouping same-position deltas together so each iteration
+    // handles one maximal constant-coverage span
+
+    let mut i = 0;
+    while i < events.len() {
+        let pos = events[i].0;
+        if pos > prev {
+            record_span(
+                prev as u32,
+                (pos - 1) as u32,
+                coverage,
+                &mut min_coverage,
+                &mut gaps,
+                &mut histogram_sums,
+                bucket_width,
+            );
+            prev = pos;
+        }
+        while i < events.len() && events[i].0 == pos {
+            coverage += events[i].1;
+            i += 1;
+        }
+    }
+    if prev <= u32::MAX as i64 {
+        record_span(
+            prev as u32,
+            u32::MAX,
+            coverage,
+            &mut min_coverage,
+            &mut gaps,
+            &mut histogram_sums,
+            bucket_width,
+        );
+    }
+
+    let histogram = if bucket_count > 0 {
+        Some(
+            histogram_sums
+                .into_iter()
+                .enumerate()
+                .map(|(bucket, sum)| {
+                    // the last bucket absorbs whatever remainder `total_locations` doesn't
+                    // split evenly into `bucket_count` equal-width buckets.
+                    let width = if bucket + 1 == bucket_count {
+                        total_locations - bucket_width * (bucket_count as u64 - 1)
+                    } else {
+                        bucket_width
+                    };
+                    (sum / width as u128) as usize
+                })
+                .collect(),
+        )
+    } else {
+        None
+    };
+
+    Ok(CoverageReport {
+        min_coverage,
+        gaps,
+        histogram,
+    })
+}
+
+fn record_span(
+    start: u32,
+    end: u32,
+    coverage: i64,
+    min_coverage: &mut usize,
+    gaps: &mut Vec<CoverageGap>,
+    histogram_sums: &mut [u128],
+    bucket_width: u64,
+) {
+    let coverage = coverage.max(0) as usize;
+    if coverage < *min_coverage {
+        *min_coverage = coverage;
+    }
+    if coverage == 0 {
+        gaps.push(CoverageGap { start, end });
+    }
+    if bucket_width == 0 {
+        return;
+    }
+    let last_index = histogram_sums.len() - 1;
+    let first_bucket = ((start as u64 / bucket_width) as usize).min(last_index);
+    let last_bucket = ((end as u64 / bucket_width) as usize).min(last_index);
+    let sums = &mut histogram_sums[first_bucket..=last_bucket];
+    for (offset, sum) in sums.iter_mut().enumerate() {
+        let bucket = first_bucket + offset;
+        let bucket_start = bucket as u64 * bucket_width;
+        let bucket_end = if bucket == last_index {
+            u32::MAX as u64
+        } else {
+            bucket_start + bucket_width - 1
+        };
+        let overlap_start = (start as u64).max(bucket_start);
+        let overlap_end = (end as u64).min(bucket_end);
+        let overlap_len = overlap_end - overlap_start + 1;
+        *sum += coverage as u128 * overlap_len as u128;
+    }
+}
+
 fn count_agents_overlaping_arc(con: &Connection, arc: DhtArc) -> Result<usize> {
     let split_arc: SplitArc = arc.into();
     let SplitArc {
@@ -178,49 +450,268 @@ fn count_agents_overlaping_arc(con: &Connection, arc: DhtArc) -> Result<usize> {
     stmt.query_row(params![start_1, end_1, start_2, end_2], |r| r.get(0))
 }
 
-fn main() -> Result<()> {
-    let con = Connection::open_in_memory()?;
+/// Delete every agent-info row signed strictly before `cutoff_ms`, using the
+/// `idx_p2p_store_signed_at_ms` index. Returns the number of rows removed.
+fn prune_before(con: &Connection, cutoff_ms: u64) -> Result<usize> {
+    con.execute("DELETE FROM p2p_store WHERE signed_at_ms < ?1", params![cutoff_ms])
+}
 
-    con.execute(
-        "CREATE TABLE IF NOT EXISTS p2p_store (
-            key             BLOB    PRIMARY KEY ON CONFLICT REPLACE,
-            blob            BLOB    NOT NULL,
-            signed_at_ms    INTEGER NOT NULL,
-            center_loc      INTEGER NOT NULL,
-            half_length     INTEGER NOT NULL,
-            arc_start1      INTEGER NULL,
-            arc_end1        INTEGER NULL,
-            arc_start2      INTEGER NULL,
-            arc_end2        INTEGER NULL
-        );",
-        [],
+/// Delete every agent-info row whose `signed_at_ms` is older than `ttl_ms` relative to
+/// `now_ms`. Returns the number of rows removed.
+fn prune_expired(con: &Connection, now_ms: u64, ttl_ms: u64) -> Result<usize> {
+    prune_before(con, now_ms.saturating_sub(ttl_ms))
+}
+
+/// Register the scalar functions used by our queries. `ring_distance(loc, center)` is the
+/// distance from `loc` to `center` on the u32 ring: the shorter of going up or down.
+fn install_functions(con: &Connection) -> Result<()> {
+    con.create_scalar_function(
+        "ring_distance",
+        2,
+        functions::FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let loc: u32 = ctx.get(0)?;
+            let center: u32 = ctx.get(1)?;
+            let diff = loc.abs_diff(center) as u64;
+            Ok(diff.min(u32::MAX as u64 - diff + 1))
+        },
+    )
+}
+
+/// Find the `limit` agents whose storage arc covers `loc`, ordered by how central `loc` is
+/// within their arc (ascending ring distance to their `center_loc`). This is the nearest-peers
+/// primitive: given a DHT location, it returns the most authoritative agents holding it.
+fn query_agents_for_loc(con: &Connection, loc: u32, limit: u32) -> Result<Vec<AgentInfo>> {
+    let mut stmt = con.prepare(
+        "SELECT blob
+        FROM p2p_store
+        WHERE (
+            arc_start1 IS NOT NULL
+            AND arc_end1 IS NOT NULL
+            AND ?1 >= arc_start1
+            AND ?1 <= arc_end1
+        )
+        OR (
+            arc_start2 IS NOT NULL
+            AND arc_end2 IS NOT NULL
+            AND ?1 >= arc_start2
+            AND ?1 <= arc_end2
+        )
+        ORDER BY ring_distance(?1, center_loc) ASC
+        LIMIT ?2;",
     )?;
+    let rows = stmt.query_map(params![loc, limit], |r| {
+        let blob: Vec<u8> = r.get(0)?;
+        Ok(blob)
+    })?;
+    rows.map(|blob| {
+        let blob = blob?;
+        rmp_serde::from_slice(&blob).map_err(|e| Error::ToSqlConversionFailure(e.into()))
+    })
+    .collect()
+}
+
+fn main() -> Result<()> {
+    let store = P2pStore::new_in_memory()?;
 
     let mut info_zero = AgentInfo::new_rand();
     info_zero.storage_arc = DhtArc::new(0, 0);
-    insert(&con, &info_zero)?;
+    store.insert(&info_zero)?;
 
     for _ in 0..10 {
         let agent_info = AgentInfo::new_rand();
-        insert(&con, &agent_info)?;
+        store.insert(&agent_info)?;
     }
 
-    dump(&con)?;
-    println!("agents covering 0: {}", count_agents_covering_loc(&con, 0)?);
+    store.dump()?;
+    println!("agents covering 0: {}", store.count_agents_covering_loc(0)?);
     println!(
         "agents covering {}: {}",
         u32::MAX,
-        count_agents_covering_loc(&con, u32::MAX)?
+        store.count_agents_covering_loc(u32::MAX)?
     );
     let mid = u32::MAX / 2;
     println!(
         "agents covering {}: {}",
         mid,
-        count_agents_covering_loc(&con, mid)?
+        store.count_agents_covering_loc(mid)?
     );
     let overlap = DhtArc::new(mid, u32::MAX / 4);
-    let res = count_agents_overlaping_arc(&con, overlap.clone())?;
+    let res = store.count_agents_overlaping_arc(overlap.clone())?;
     println!("agents overlapping {:?}: {}", &overlap, res);
 
+    let report = store.analyze_coverage(Some(8))?;
+    println!("min coverage: {}", report.min_coverage);
+    println!("gaps: {}", report.gaps.len());
+    println!("histogram: {:?}", report.histogram);
+
+    let pruned = store.prune_before(0)?;
+    println!("pruned (signed before 0): {}", pruned);
+
+    let nearest = store.query_agents_for_loc(mid, 3)?;
+    println!("nearest agents for {}: {}", mid, nearest.len());
+
+    let mem_store = MemoryArcStore::new();
+    mem_store
+        .insert(&info_zero)
+        .map_err(|e| Error::ToSqlConversionFailure(e.into()))?;
+    println!(
+        "(in-memory backend) agents covering 0: {}",
+        mem_store
+            .count_agents_covering_loc(0)
+            .map_err(|e| Error::ToSqlConversionFailure(e.into()))?
+    );
+
+    // exercise `store` through the same ArcStore trait seam as mem_store above, so
+    // P2pStore's ArcStore impl (not just its inherent methods) gets run too
+    let arc_store: &dyn ArcStore = &store;
+    arc_store.dump().map_err(|e| Error::ToSqlConversionFailure(e.into()))?;
+    println!(
+        "(sqlite backend via ArcStore) agents overlapping {:?}: {}",
+        &overlap,
+        arc_store
+            .count_agents_overlaping_arc(overlap.clone())
+            .map_err(|e| Error::ToSqlConversionFailure(e.into()))?
+    );
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent_with_arc(arc: DhtArc) -> AgentInfo {
+        let mut info = AgentInfo::new_rand();
+        info.storage_arc = arc;
+        info
+    }
+
+    #[test]
+    fn zero_length_arc_covers_nothing() {
+        let store = P2pStore::new_in_memory().unwrap();
+        store.insert(&agent_with_arc(DhtArc::new(12345, 0))).unwrap();
+
+        let report = store.analyze_coverage(None).unwrap();
+        assert_eq!(report.min_coverage, 0);
+        assert_eq!(
+            report.gaps,
+            vec![CoverageGap {
+                start: 0,
+                end: u32::MAX
+            }]
+        );
+    }
+
+    #[test]
+    fn wraparound_arc_touching_u32_max_leaves_a_single_location_gap() {
+        let store = P2pStore::new_in_memory().unwrap();
+        // center 0, half_length u32::MAX wraps around to cover [1, u32::MAX], missing only
+        // location 0 - also exercises push_interval's u32::MAX special case (no +1 decrement
+        // event, since the interval already runs to the edge of the ring).
+        store
+            .insert(&agent_with_arc(DhtArc::new(0, u32::MAX)))
+            .unwrap();
+
+        let report = store.analyze_coverage(None).unwrap();
+        assert_eq!(report.min_coverage, 0);
+        assert_eq!(report.gaps, vec![CoverageGap { start: 0, end: 0 }]);
+    }
+
+    #[test]
+    fn two_arcs_exactly_tile_the_whole_ring() {
+        let store = P2pStore::new_in_memory().unwrap();
+        // covers [1, u32::MAX]
+        store
+            .insert(&agent_with_arc(DhtArc::new(0, u32::MAX)))
+            .unwrap();
+        // covers exactly {0}
+        store
+            .insert(&agent_with_arc(DhtArc::new(1u32 << 31, 1u32 << 31)))
+            .unwrap();
+
+        let report = store.analyze_coverage(None).unwrap();
+        assert_eq!(report.min_coverage, 1);
+        assert!(report.gaps.is_empty());
+    }
+
+    #[test]
+    fn histogram_remainder_bucket_still_averages_uniform_coverage_correctly() {
+        let store = P2pStore::new_in_memory().unwrap();
+        store
+            .insert(&agent_with_arc(DhtArc::new(0, u32::MAX)))
+            .unwrap();
+        store
+            .insert(&agent_with_arc(DhtArc::new(1u32 << 31, 1u32 << 31)))
+            .unwrap();
+
+        // 3 buckets don't divide 2^32 evenly, so the last bucket is wider than the other two.
+        // Coverage is uniformly 1 across the whole ring, so every bucket's weighted average
+        // should still land on 1 regardless of its width.
+        let report = store.analyze_coverage(Some(3)).unwrap();
+        assert_eq!(report.histogram, Some(vec![1, 1, 1]));
+    }
+
+    #[test]
+    fn prune_expired_keeps_rows_exactly_at_the_ttl_boundary() {
+        let store = P2pStore::new_in_memory().unwrap();
+        let mut info = AgentInfo::new_rand();
+        info.signed_at_ms = 1_000;
+        store.insert(&info).unwrap();
+
+        // now_ms - ttl_ms == signed_at_ms exactly: prune_before deletes strictly-less-than the
+        // cutoff, so a row signed right at the boundary must survive.
+        assert_eq!(store.prune_expired(2_000, 1_000).unwrap(), 0);
+        // one ms later the same row is now strictly older than the cutoff.
+        assert_eq!(store.prune_expired(2_001, 1_000).unwrap(), 1);
+    }
+
+    #[test]
+    fn prune_expired_saturates_instead_of_underflowing_when_ttl_exceeds_now() {
+        let store = P2pStore::new_in_memory().unwrap();
+        let mut info = AgentInfo::new_rand();
+        info.signed_at_ms = 0;
+        store.insert(&info).unwrap();
+
+        // ttl_ms > now_ms would underflow a plain subtraction; saturating_sub clamps the
+        // cutoff to 0, so nothing signed at/after time 0 is pruned.
+        assert_eq!(store.prune_expired(5, 100).unwrap(), 0);
+    }
+
+    #[test]
+    fn ring_distance_takes_the_shorter_way_around() {
+        let con = Connection::open_in_memory().unwrap();
+        install_functions(&con).unwrap();
+
+        let d: u32 = con
+            .query_row("SELECT ring_distance(5, 10)", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(d, 5);
+
+        // going straight from 0 up to u32::MAX - 4 is almost the whole ring; wrapping the
+        // other way through u32::MAX back to 0 is only 5 steps.
+        let d: u32 = con
+            .query_row(
+                &format!("SELECT ring_distance(0, {})", u32::MAX - 4),
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(d, 5);
+    }
+
+    #[test]
+    fn query_agents_for_loc_orders_by_ring_distance_and_respects_limit() {
+        let store = P2pStore::new_in_memory().unwrap();
+        let loc = 1_000_000u32;
+        let near = agent_with_arc(DhtArc::new(loc, u32::MAX / 4));
+        let far = agent_with_arc(DhtArc::new(loc.wrapping_add(10_000), u32::MAX / 4));
+        // insert the farther agent first so a correct ORDER BY is actually required to pass.
+        store.insert(&far).unwrap();
+        store.insert(&near).unwrap();
+
+        let nearest = store.query_agents_for_loc(loc, 1).unwrap();
+        assert_eq!(nearest.len(), 1);
+        assert_eq!(nearest[0].key, near.key);
+    }
+}
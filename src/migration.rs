@@ -0,0 +1,48 @@
+use rusqlite::{Connection, Result};
+
+/// A single ordered schema migration: bump `PRAGMA user_version` to `version` by running `sql`.
+pub struct Migration {
+    pub version: i64,
+    pub sql: &'static str,
+}
+
+/// All migrations, in the order they must be applied. Opening an existing db at a lower
+/// version only runs the steps after its current `user_version`, so adding a new indexed
+/// column is just a matter of appending another entry here.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        sql: "CREATE TABLE p2p_store (
+            key             BLOB    PRIMARY KEY ON CONFLICT REPLACE,
+            blob            BLOB    NOT NULL,
+            signed_at_ms    INTEGER NOT NULL,
+            center_loc      INTEGER NOT NULL,
+            half_length     INTEGER NOT NULL,
+            arc_start1      INTEGER NULL,
+            arc_end1        INTEGER NULL,
+            arc_start2      INTEGER NULL,
+            arc_end2        INTEGER NULL
+        );",
+    },
+    Migration {
+        version: 2,
+        // speeds up prune_before/prune_expired, which delete by signed_at_ms range
+        sql: "CREATE INDEX idx_p2p_store_signed_at_ms ON p2p_store (signed_at_ms);",
+    },
+];
+
+/// Apply every pending migration, each in its own transaction, bumping `user_version` as it
+/// goes so a later call on the same db only runs what's still outstanding.
+pub fn migrate(con: &mut Connection) -> Result<()> {
+    let current: i64 = con.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+    for m in MIGRATIONS {
+        if m.version <= current {
+            continue;
+        }
+        let tx = con.transaction()?;
+        tx.execute_batch(m.sql)?;
+        tx.pragma_update(None, "user_version", m.version)?;
+        tx.commit()?;
+    }
+    Ok(())
+}